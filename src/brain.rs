@@ -0,0 +1,94 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+fn sample_standard_normal(rng: &mut impl Rng) -> f32 {
+    // Box-Muller transform, since we only have a uniform sampler to work with.
+    let u1: f32 = rng.gen_range(f32::EPSILON..1.0);
+    let u2: f32 = rng.gen_range(0.0..1.0);
+
+    (-2.0 * u1.ln()).sqrt() * (std::f32::consts::TAU * u2).cos()
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Matrix {
+    rows: usize,
+    cols: usize,
+    data: Vec<f32>,
+}
+
+impl Matrix {
+    // `cols` includes the extra bias column, so the fan-in is `cols - 1`.
+    fn random(rows: usize, cols: usize, rng: &mut impl Rng) -> Self {
+        let scale = (2.0 / (cols - 1) as f32).sqrt();
+
+        let data = (0..rows * cols)
+            .map(|_| sample_standard_normal(rng) * scale)
+            .collect();
+
+        Matrix { rows, cols, data }
+    }
+
+    fn mutate(&self, mutate_chance: f64, rng: &mut impl Rng) -> Matrix {
+        let data = self.data.iter()
+            .map(|&value| if rng.gen_bool(mutate_chance) {
+                sample_standard_normal(rng)
+            } else {
+                value
+            })
+            .collect();
+
+        Matrix { rows: self.rows, cols: self.cols, data }
+    }
+
+    fn multiply_vec(&self, input: &[f32]) -> Vec<f32> {
+        assert_eq!(input.len(), self.cols);
+
+        (0..self.rows)
+            .map(|row| {
+                (0..self.cols)
+                    .map(|col| self.data[row * self.cols + col] * input[col])
+                    .sum()
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Brain {
+    pub config: Vec<usize>,
+    pub weights: Vec<Matrix>,
+}
+
+impl Brain {
+    pub fn new(config: Vec<usize>, rng: &mut impl Rng) -> Self {
+        let weights = config.windows(2)
+            .map(|layers| Matrix::random(layers[1], layers[0] + 1, rng))
+            .collect();
+
+        Brain { config, weights }
+    }
+
+    pub fn feed_forward(&self, inputs: &[f32]) -> Vec<f32> {
+        let last_layer = self.weights.len() - 1;
+
+        self.weights.iter().enumerate().fold(inputs.to_vec(), |mut activations, (i, weights)| {
+            activations.push(1.0); // bias
+
+            let output = weights.multiply_vec(&activations);
+
+            if i == last_layer {
+                output.into_iter().map(|value| value.tanh()).collect()
+            } else {
+                output.into_iter().map(|value| value.max(0.0)).collect() // ReLU
+            }
+        })
+    }
+
+    pub fn mutate(&self, mutate_chance: f64, rng: &mut impl Rng) -> Brain {
+        let weights = self.weights.iter()
+            .map(|matrix| matrix.mutate(mutate_chance, rng))
+            .collect();
+
+        Brain { config: self.config.clone(), weights }
+    }
+}
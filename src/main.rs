@@ -1,13 +1,38 @@
+use std::collections::HashSet;
 use std::f32::INFINITY;
+use std::fs;
 
+use brain::Brain;
 use food::Food;
-use nannou::{color, event::{Key, Update}, glam::{vec2, Vec2}, prelude::Pow, App, Draw, Frame};
+use nannou::{color, event::{Key, Update}, geom::Rect, glam::{vec2, Vec2}, prelude::Pow, App, Draw, Frame};
 use nannou_egui::{egui, Egui};
 use rand::{rngs::ThreadRng, Rng};
-use steering_agent::{Dna, SteeringAgent};
+use pheromone::PheromoneGrid;
+use serde::{Deserialize, Serialize};
+use spatial_grid::SpatialGrid;
+use steering_agent::{CrossoverMethod, Dna, Neighbor, SteeringAgent};
 
 mod steering_agent;
 mod food;
+mod brain;
+mod pheromone;
+mod spatial_grid;
+
+const BRAIN_CONFIG: [usize; 3] = [7, 8, 2];
+const MATE_DETECTION_RADIUS: f32 = 128.0;
+const PHEROMONE_CELL_SIZE: f32 = 16.0;
+const PHEROMONE_DECAY_FACTOR: f32 = 0.99;
+const SPATIAL_GRID_CELL_SIZE: f32 = 64.0;
+const SAVE_FILE_PATH: &str = "genomes.json";
+const GENERATION_STEP_BUDGET: usize = 1800;
+
+// Positions aren't saved; food/poison counts just let a reload regenerate at scale.
+#[derive(Serialize, Deserialize)]
+struct SaveFile {
+    food_count: usize,
+    poison_count: usize,
+    genomes: Vec<(Dna, Option<Brain>)>,
+}
 
 
 struct Model {
@@ -17,13 +42,25 @@ struct Model {
     agents: Vec<SteeringAgent>,
     food: Vec<Food>,
     poison: Vec<Food>,
-    
+    pheromones: PheromoneGrid,
+
     minimum_food_count: usize,
     minimum_poison_count: usize,
     minimum_agent_count: usize,
 
     debug: bool,
-    follow_mouse: bool
+    follow_mouse: bool,
+    brain_mode: bool,
+    crossover_method: CrossoverMethod,
+
+    generational_mode: bool,
+    generation: usize,
+    generation_step: usize,
+    // Dna/brain/fitness of everyone who's died this generation; the survivors are added
+    // when the generation ends.
+    generation_archive: Vec<(Dna, Option<Brain>, f32)>,
+    best_fitness: f32,
+    average_fitness: f32,
 }
 
 fn main() {
@@ -85,10 +122,13 @@ fn init(app: &App) -> Model {
 
     let egui = Egui::from_window(&window);
 
+    let pheromones = PheromoneGrid::new(app.window_rect(), PHEROMONE_CELL_SIZE);
+
     Model {
         agents,
         food,
         poison,
+        pheromones,
         rng,
         egui,
 
@@ -97,20 +137,69 @@ fn init(app: &App) -> Model {
         minimum_agent_count: agent_count,
         debug: false,
         follow_mouse: false,
+        brain_mode: false,
+        crossover_method: CrossoverMethod::Uniform,
+
+        generational_mode: false,
+        generation: 0,
+        generation_step: 0,
+        generation_archive: Vec::new(),
+        best_fitness: 0.,
+        average_fitness: 0.,
+    }
+}
+
+fn find_closest_food(
+    position: Vec2,
+    max_distance: f32,
+    food: &[Food],
+    grid: &SpatialGrid,
+    excluded: &HashSet<usize>,
+) -> Option<(usize, Food)> {
+    let mut closest = None;
+    let mut closest_distance = INFINITY;
+
+    let max_distance_squared = max_distance.pow(2);
+
+    for index in grid.query_nearest(position, max_distance) {
+        if excluded.contains(&index) {
+            continue;
+        }
+
+        let distance = position.distance_squared(food[index].position);
+
+        if distance < max_distance_squared && distance < closest_distance {
+            closest = Some((index, food[index]));
+            closest_distance = distance;
+        }
     }
+
+    closest
 }
 
-fn find_closest_food(position: Vec2, max_distance: f32, food: &Vec<Food>) -> Option<(usize, Food)> {
+fn find_closest_agent(
+    position: Vec2,
+    max_distance: f32,
+    exclude_index: usize,
+    agents: &[SteeringAgent],
+    grid: &SpatialGrid,
+) -> Option<usize> {
     let mut closest = None;
     let mut closest_distance = INFINITY;
 
     let max_distance_squared = max_distance.pow(2);
 
-    for (index, food) in food.iter().enumerate() {
-        let distance = position.distance_squared(food.position);
+    // Not `query_nearest`: the agent itself sits at distance 0 in the grid and would end
+    // that search after its own cell.
+    for index in grid.query_radius(position, max_distance) {
+        if index == exclude_index {
+            continue;
+        }
+
+        let distance = position.distance_squared(agents[index].position);
 
         if distance < max_distance_squared && distance < closest_distance {
-            closest = Some((index, *food));
+            closest = Some(index);
             closest_distance = distance;
         }
     }
@@ -118,22 +207,109 @@ fn find_closest_food(position: Vec2, max_distance: f32, food: &Vec<Food>) -> Opt
     closest
 }
 
+// Roulette-wheel selection: picks one archive entry with probability proportional to
+// its weight.
+fn select_parent<'a>(
+    archive: &'a [(Dna, Option<Brain>, f32)],
+    weights: &[f32],
+    total_weight: f32,
+    rng: &mut impl Rng,
+) -> &'a (Dna, Option<Brain>, f32) {
+    let mut pick = rng.gen_range(0.0..total_weight);
+
+    for (entry, &weight) in archive.iter().zip(weights) {
+        if pick < weight {
+            return entry;
+        }
+
+        pick -= weight;
+    }
+
+    // Float rounding can make `pick` overshoot `total_weight` by a hair; that's not a
+    // logic error, so fall back to the last entry instead of panicking.
+    archive.last().unwrap_or(&archive[0])
+}
+
+// Ends the generation and fills the next one via fitness-proportional selection.
+fn advance_generation(model: &mut Model, window_rect: Rect) {
+    for agent in &model.agents {
+        model.generation_archive.push((agent.dna, agent.brain.clone(), agent.fitness()));
+    }
+
+    let fitnesses: Vec<f32> = model.generation_archive.iter().map(|&(_, _, fitness)| fitness).collect();
+
+    model.best_fitness = fitnesses.iter().cloned().fold(f32::MIN, f32::max);
+    model.average_fitness = fitnesses.iter().sum::<f32>() / fitnesses.len() as f32;
+
+    // Roulette-wheel weights have to be non-negative.
+    let min_fitness = fitnesses.iter().cloned().fold(f32::INFINITY, f32::min);
+    let weights: Vec<f32> = fitnesses.iter().map(|fitness| fitness - min_fitness + 1.0).collect();
+    let total_weight: f32 = weights.iter().sum();
+
+    let mut next_generation = Vec::with_capacity(model.minimum_agent_count);
+
+    for _ in 0..model.minimum_agent_count {
+        let (parent_a_dna, parent_a_brain, _) = select_parent(&model.generation_archive, &weights, total_weight, &mut model.rng);
+        let (parent_b_dna, _, _) = select_parent(&model.generation_archive, &weights, total_weight, &mut model.rng);
+
+        let child_dna = parent_a_dna.crossover(parent_b_dna, model.crossover_method, &mut model.rng).mutate(0.75, &mut model.rng);
+
+        let mut child = SteeringAgent::new(get_random_position(window_rect, &mut model.rng), &child_dna);
+
+        if let Some(brain) = parent_a_brain {
+            child = child.with_brain(brain.mutate(0.75, &mut model.rng));
+        }
+
+        next_generation.push(child);
+    }
+
+    model.agents = next_generation;
+    model.generation_archive.clear();
+    model.generation += 1;
+    model.generation_step = 0;
+}
+
 fn update(app: &App, model: &mut Model, update: Update) {
     let delta = update.since_last.as_secs_f32();
 
     let max_hunger_before_dead = 128.0;
     let max_hunger_before_search = 20.0;
 
-    let mut newborns = Vec::new();
+    model.pheromones.decay(PHEROMONE_DECAY_FACTOR);
 
     let mut average_age = 0.;
     let mut agent_count = 0;
 
+    // Parallels the surviving agents in `model.agents` after `retain_mut`.
+    let mut wants_to_reproduce = Vec::new();
+
+    // Removed from model.food/model.poison after retain_mut, so indices stay valid.
+    let mut food_to_remove = HashSet::new();
+    let mut poison_to_remove = HashSet::new();
+
+    // Dna/brain/fitness of whoever died this tick, for generational mode's selection.
+    let mut generation_deaths = Vec::new();
+
+    // Built once before retain_mut, which holds model.agents mutably.
+    let neighbor_snapshot: Vec<Neighbor> = model.agents.iter()
+        .map(|agent| Neighbor { position: agent.position, velocity: agent.velocity })
+        .collect();
+
+    let food_grid = SpatialGrid::build(model.food.iter().map(|food| food.position), SPATIAL_GRID_CELL_SIZE);
+    let poison_grid = SpatialGrid::build(model.poison.iter().map(|poison| poison.position), SPATIAL_GRID_CELL_SIZE);
+    let agent_grid = SpatialGrid::build(neighbor_snapshot.iter().map(|neighbor| neighbor.position), SPATIAL_GRID_CELL_SIZE);
+
+    let mut agent_index = 0;
+
     model.agents.retain_mut(|agent| {
+        let current_index = agent_index;
+        agent_index += 1;
+
         if model.follow_mouse {
             agent.arrive(app.mouse.position(), 1.0);
             agent.hunger = 0.;
             agent.update();
+            wants_to_reproduce.push(false);
             return true;
         }
 
@@ -145,48 +321,84 @@ fn update(app: &App, model: &mut Model, update: Update) {
         let mut should_retain = true;
 
         let mut touched_poison = false;
-        model.poison.retain(|poison| {
+        for index in poison_grid.query_radius(agent.position, agent.dna.poison_detection_radius) {
+            if poison_to_remove.contains(&index) {
+                continue;
+            }
+
+            let poison = model.poison[index];
             let distance_squared = agent.position.distance_squared(poison.position);
 
             if distance_squared < poison.radius.pow(2) {
                 touched_poison = true;
-                return false;
-            } else {
-                if distance_squared < agent.dna.poison_detection_radius.pow(2) {
-                    agent.flee(poison.position, agent.dna.poison_force_multiplier);
-                }
-                true
+                agent.poison_touched += 1;
+                poison_to_remove.insert(index);
+            } else if !model.brain_mode && distance_squared < agent.dna.poison_detection_radius.pow(2) {
+                agent.flee(poison.position, agent.dna.poison_force_multiplier);
             }
-        });
+        }
 
         if touched_poison {
             should_retain = false;
         } else if agent.hunger > max_hunger_before_dead {
             should_retain = false;
+        } else if model.brain_mode {
+            let closest_food = find_closest_food(agent.position, agent.dna.food_detection_radius, &model.food, &food_grid, &food_to_remove);
+            let closest_poison = find_closest_food(agent.position, agent.dna.poison_detection_radius, &model.poison, &poison_grid, &poison_to_remove);
+
+            agent.think(
+                closest_food.map(|(_, food)| food.position),
+                closest_poison.map(|(_, poison)| poison.position),
+                max_hunger_before_dead,
+            );
+
+            if let Some((index, food)) = closest_food {
+                if agent.position.distance_squared(food.position) < food.radius.pow(2) {
+                    food_to_remove.insert(index);
+                    agent.hunger -= food.saturation;
+                    agent.food_eaten += 1;
+                    agent.deposit_pheromone(&mut model.pheromones, agent.dna.pheromone_deposit_amount);
+                }
+            }
         } else if agent.hunger > max_hunger_before_search {
-            let closest_food = find_closest_food(agent.position, agent.dna.food_detection_radius, &model.food);
+            let closest_food = find_closest_food(agent.position, agent.dna.food_detection_radius, &model.food, &food_grid, &food_to_remove);
 
             if let Some((index, food)) = closest_food {
                 agent.arrive(food.position, agent.dna.food_force_multiplier);
 
                 if agent.position.distance_squared(food.position) < food.radius.pow(2) {
-                    model.food.remove(index);
+                    food_to_remove.insert(index);
                     agent.hunger -= food.saturation;
+                    agent.food_eaten += 1;
+                    agent.deposit_pheromone(&mut model.pheromones, agent.dna.pheromone_deposit_amount);
                 }
             } else {
-                agent.wander(app.window_rect(), &mut model.rng);
+                let following_trail = agent.follow_pheromones(&model.pheromones, agent.dna.pheromone_follow_strength);
+
+                if !following_trail {
+                    agent.wander(app.window_rect(), &mut model.rng);
+                }
             }
         } else {
             agent.wander(app.window_rect(), &mut model.rng);
         }
 
-        agent.update();
+        let neighbors: Vec<Neighbor> = agent_grid.query_radius(agent.position, agent.dna.flock_detection_radius).into_iter()
+            .filter(|&index| index != current_index)
+            .map(|index| neighbor_snapshot[index])
+            .collect();
 
-        let should_create_child = model.rng.gen_bool(0.001);
+        agent.separation(&neighbors, agent.dna.flock_detection_radius, agent.dna.separation_force_multiplier);
+        agent.alignment(&neighbors, agent.dna.flock_detection_radius, agent.dna.alignment_force_multiplier);
+        agent.cohesion(&neighbors, agent.dna.flock_detection_radius, agent.dna.cohesion_force_multiplier);
 
-        if should_create_child {
-            let new_agent = SteeringAgent::new(agent.position, &agent.dna.mutate(0.75, &mut model.rng));
-            newborns.push(new_agent);
+        agent.update();
+
+        if should_retain {
+            // Generational mode fills the population at generation boundaries instead.
+            wants_to_reproduce.push(!model.generational_mode && model.rng.gen_bool(0.001));
+        } else if model.generational_mode {
+            generation_deaths.push((agent.dna, agent.brain.clone(), agent.fitness()));
         }
 
         should_retain
@@ -194,6 +406,47 @@ fn update(app: &App, model: &mut Model, update: Update) {
 
     average_age /= agent_count as f32;
 
+    // Sorted descending so removing one index doesn't shift the ones still to come.
+    let mut eaten_food: Vec<usize> = food_to_remove.into_iter().collect();
+    eaten_food.sort_unstable_by(|a, b| b.cmp(a));
+    for index in eaten_food {
+        model.food.remove(index);
+    }
+
+    let mut touched_poison_indices: Vec<usize> = poison_to_remove.into_iter().collect();
+    touched_poison_indices.sort_unstable_by(|a, b| b.cmp(a));
+    for index in touched_poison_indices {
+        model.poison.remove(index);
+    }
+
+    let mate_grid = SpatialGrid::build(model.agents.iter().map(|agent| agent.position), SPATIAL_GRID_CELL_SIZE);
+
+    let mut newborns = Vec::new();
+
+    for (index, &wants_to_reproduce) in wants_to_reproduce.iter().enumerate() {
+        if !wants_to_reproduce {
+            continue;
+        }
+
+        let agent = &model.agents[index];
+
+        let mate_index = find_closest_agent(agent.position, MATE_DETECTION_RADIUS, index, &model.agents, &mate_grid);
+
+        let child_dna = if let Some(mate_index) = mate_index {
+            agent.dna.crossover(&model.agents[mate_index].dna, model.crossover_method, &mut model.rng)
+        } else {
+            agent.dna
+        };
+
+        let mut new_agent = SteeringAgent::new(agent.position, &child_dna.mutate(0.75, &mut model.rng));
+
+        if let Some(brain) = &agent.brain {
+            new_agent = new_agent.with_brain(brain.mutate(0.75, &mut model.rng));
+        }
+
+        newborns.push(new_agent);
+    }
+
     model.agents.append(&mut newborns);
 
     let window_width = app.window_rect().w();
@@ -225,15 +478,29 @@ fn update(app: &App, model: &mut Model, update: Update) {
         }
     }
 
-    if model.agents.len() < model.minimum_agent_count {
+    if !model.generational_mode && model.agents.len() < model.minimum_agent_count {
         let difference = model.minimum_agent_count - model.agents.len();
 
         for _ in 0..difference {
-            model.agents.push(SteeringAgent::new(
+            let mut new_agent = SteeringAgent::new(
                 vec2(model.rng.gen_range(-window_width/2.0..window_width/2.0), model.rng.gen_range(-window_height/2.0..window_height/2.0)),
-                    &Dna::random(&mut model.rng)
-                )
+                &Dna::random(&mut model.rng),
             );
+
+            if model.brain_mode {
+                new_agent = new_agent.with_brain(Brain::new(BRAIN_CONFIG.to_vec(), &mut model.rng));
+            }
+
+            model.agents.push(new_agent);
+        }
+    }
+
+    if model.generational_mode {
+        model.generation_archive.append(&mut generation_deaths);
+        model.generation_step += 1;
+
+        if model.agents.is_empty() || model.generation_step >= GENERATION_STEP_BUDGET {
+            advance_generation(model, app.window_rect());
         }
     }
 
@@ -244,6 +511,12 @@ fn update(app: &App, model: &mut Model, update: Update) {
     egui::Window::new("Steering Life").show(&ctx, |ui| {
         ui.label(format!("Average lifespan: {:.2}", average_age));
 
+        if model.generational_mode {
+            ui.label(format!("Generation: {}", model.generation));
+            ui.label(format!("Best fitness: {:.2}", model.best_fitness));
+            ui.label(format!("Average fitness: {:.2}", model.average_fitness));
+        }
+
         ui.label(format!("{:.2} FPS", app.fps()));
     });
 }
@@ -252,10 +525,108 @@ fn raw_window_event(_app: &App, model: &mut Model, event: &nannou::winit::event:
     model.egui.handle_raw_event(event);
 }
 
-fn key_pressed(_app: &App, model: &mut Model, key: Key) {
+fn save_genomes(model: &Model) {
+    let save_file = SaveFile {
+        food_count: model.food.len(),
+        poison_count: model.poison.len(),
+        genomes: model.agents.iter().map(|agent| (agent.dna, agent.brain.clone())).collect(),
+    };
+
+    let json = match serde_json::to_string_pretty(&save_file) {
+        Ok(json) => json,
+        Err(error) => {
+            eprintln!("Failed to serialize genomes: {}", error);
+            return;
+        },
+    };
+
+    if let Err(error) = fs::write(SAVE_FILE_PATH, json) {
+        eprintln!("Failed to write {}: {}", SAVE_FILE_PATH, error);
+    }
+}
+
+fn load_genomes(model: &mut Model, window_rect: Rect) {
+    let json = match fs::read_to_string(SAVE_FILE_PATH) {
+        Ok(json) => json,
+        Err(error) => {
+            eprintln!("Failed to read {}: {}", SAVE_FILE_PATH, error);
+            return;
+        },
+    };
+
+    let save_file: SaveFile = match serde_json::from_str(&json) {
+        Ok(save_file) => save_file,
+        Err(error) => {
+            eprintln!("Failed to parse {}: {}", SAVE_FILE_PATH, error);
+            return;
+        },
+    };
+
+    model.agents = save_file.genomes.iter()
+        .map(|(dna, brain)| {
+            let agent = SteeringAgent::new(get_random_position(window_rect, &mut model.rng), dna);
+
+            match brain {
+                Some(brain) => agent.with_brain(brain.clone()),
+                None => agent,
+            }
+        })
+        .collect();
+
+    model.food = (0..save_file.food_count)
+        .map(|_| Food::new_food(get_random_position(window_rect, &mut model.rng), &mut model.rng))
+        .collect();
+
+    model.poison = (0..save_file.poison_count)
+        .map(|_| Food::new_poison(get_random_position(window_rect, &mut model.rng), &mut model.rng))
+        .collect();
+
+    model.minimum_agent_count = model.agents.len();
+    model.minimum_food_count = model.food.len();
+    model.minimum_poison_count = model.poison.len();
+
+    // The loaded population isn't a continuation of whatever generation was running.
+    model.generation = 0;
+    model.generation_step = 0;
+    model.generation_archive.clear();
+}
+
+fn get_random_position(rect: Rect, rng: &mut impl Rng) -> Vec2 {
+    vec2(
+        rng.gen_range(-rect.w()/2.0..rect.w()/2.0),
+        rng.gen_range(-rect.h()/2.0..rect.h()/2.0),
+    )
+}
+
+fn key_pressed(app: &App, model: &mut Model, key: Key) {
     match key {
         Key::D => model.debug = !model.debug,
         Key::F => model.follow_mouse = !model.follow_mouse,
+        Key::C => {
+            model.crossover_method = match model.crossover_method {
+                CrossoverMethod::Uniform => CrossoverMethod::SinglePoint,
+                CrossoverMethod::SinglePoint => CrossoverMethod::Uniform,
+            };
+        },
+        Key::B => {
+            model.brain_mode = !model.brain_mode;
+
+            if model.brain_mode {
+                for agent in model.agents.iter_mut() {
+                    if agent.brain.is_none() {
+                        agent.brain = Some(Brain::new(BRAIN_CONFIG.to_vec(), &mut model.rng));
+                    }
+                }
+            }
+        },
+        Key::S => save_genomes(model),
+        Key::L => load_genomes(model, app.window_rect()),
+        Key::G => {
+            model.generational_mode = !model.generational_mode;
+            model.generation = 0;
+            model.generation_step = 0;
+            model.generation_archive.clear();
+        },
         _ => {},
     }
 }
@@ -333,12 +704,31 @@ fn draw_food_and_poison(model: &Model, draw: &Draw) {
     }
 }
 
+fn draw_pheromones(model: &Model, draw: &Draw) {
+    let cell_size = model.pheromones.cell_size();
+
+    for (position, intensity) in model.pheromones.cells() {
+        if intensity <= 0.01 {
+            continue;
+        }
+
+        draw.rect()
+            .xy(position)
+            .wh(vec2(cell_size, cell_size))
+            .rgba(0.2, 0.4, 1.0, intensity.min(1.0) * 0.4);
+    }
+}
+
 fn draw(app: &App, model: &Model, frame: Frame) {
     let draw = app.draw();
 
     draw.background().color(color::gray(0.1));
 
-    draw_agents(model, &draw);    
+    if model.debug {
+        draw_pheromones(model, &draw);
+    }
+
+    draw_agents(model, &draw);
 
     draw_food_and_poison(model, &draw);
 
@@ -0,0 +1,75 @@
+use nannou::geom::Rect;
+use nannou::glam::{vec2, Vec2};
+
+// Fixed-size cells, each holding a scalar pheromone intensity that decays every tick.
+pub struct PheromoneGrid {
+    cell_size: f32,
+    width: usize,
+    height: usize,
+    origin: Vec2,
+    intensities: Vec<f32>,
+}
+
+impl PheromoneGrid {
+    pub fn new(window_rect: Rect, cell_size: f32) -> Self {
+        let width = (window_rect.w() / cell_size).ceil().max(1.) as usize;
+        let height = (window_rect.h() / cell_size).ceil().max(1.) as usize;
+
+        PheromoneGrid {
+            cell_size,
+            width,
+            height,
+            origin: vec2(-window_rect.w() / 2., -window_rect.h() / 2.),
+            intensities: vec![0.; width * height],
+        }
+    }
+
+    fn cell_coords(&self, position: Vec2) -> Option<(usize, usize)> {
+        let relative = position - self.origin;
+
+        let x = (relative.x / self.cell_size).floor();
+        let y = (relative.y / self.cell_size).floor();
+
+        if x < 0. || y < 0. || x as usize >= self.width || y as usize >= self.height {
+            return None;
+        }
+
+        Some((x as usize, y as usize))
+    }
+
+    pub fn decay(&mut self, decay_factor: f32) {
+        for intensity in self.intensities.iter_mut() {
+            *intensity *= decay_factor;
+        }
+    }
+
+    pub fn deposit(&mut self, position: Vec2, amount: f32) {
+        if let Some((x, y)) = self.cell_coords(position) {
+            self.intensities[y * self.width + x] += amount;
+        }
+    }
+
+    pub fn intensity_at(&self, position: Vec2) -> f32 {
+        self.cell_coords(position)
+            .map(|(x, y)| self.intensities[y * self.width + x])
+            .unwrap_or(0.)
+    }
+
+    pub fn cell_size(&self) -> f32 {
+        self.cell_size
+    }
+
+    pub fn cells(&self) -> impl Iterator<Item = (Vec2, f32)> + '_ {
+        self.intensities.iter().enumerate().map(move |(index, &intensity)| {
+            let x = index % self.width;
+            let y = index / self.width;
+
+            let position = self.origin + vec2(
+                (x as f32 + 0.5) * self.cell_size,
+                (y as f32 + 0.5) * self.cell_size,
+            );
+
+            (position, intensity)
+        })
+    }
+}
@@ -0,0 +1,85 @@
+use nannou::glam::Vec2;
+use std::collections::HashMap;
+
+// Buckets point-like items (food, poison, agents) by cell so nearby-item queries don't
+// have to scan everything. Rebuilt fresh each frame from the current positions.
+pub struct SpatialGrid {
+    cell_size: f32,
+    cells: HashMap<(i32, i32), Vec<usize>>,
+    positions: Vec<Vec2>,
+}
+
+impl SpatialGrid {
+    pub fn build(positions: impl IntoIterator<Item = Vec2>, cell_size: f32) -> Self {
+        let mut cells: HashMap<(i32, i32), Vec<usize>> = HashMap::new();
+        let positions: Vec<Vec2> = positions.into_iter().collect();
+
+        for (index, &position) in positions.iter().enumerate() {
+            cells.entry(Self::cell_coord(position, cell_size)).or_default().push(index);
+        }
+
+        SpatialGrid { cell_size, cells, positions }
+    }
+
+    fn cell_coord(position: Vec2, cell_size: f32) -> (i32, i32) {
+        ((position.x / cell_size).floor() as i32, (position.y / cell_size).floor() as i32)
+    }
+
+    // Expands ring by ring from `position`'s cell, stopping once every cell in the next
+    // ring is farther away than the closest candidate found so far — that candidate
+    // can't be beaten by anything further out.
+    pub fn query_nearest(&self, position: Vec2, max_distance: f32) -> Vec<usize> {
+        let (cx, cy) = Self::cell_coord(position, self.cell_size);
+        let max_ring = (max_distance / self.cell_size).ceil() as i32;
+
+        let mut candidates = Vec::new();
+        let mut best_distance_squared = max_distance * max_distance;
+
+        for ring in 0..=max_ring {
+            if ring > 0 {
+                let min_possible_distance = (ring - 1) as f32 * self.cell_size;
+
+                if min_possible_distance * min_possible_distance >= best_distance_squared {
+                    break;
+                }
+            }
+
+            for dx in -ring..=ring {
+                for dy in -ring..=ring {
+                    if ring > 0 && dx.abs() != ring && dy.abs() != ring {
+                        continue; // interior cells were already visited by a smaller ring
+                    }
+
+                    if let Some(indices) = self.cells.get(&(cx + dx, cy + dy)) {
+                        for &index in indices {
+                            let distance_squared = position.distance_squared(self.positions[index]);
+                            best_distance_squared = best_distance_squared.min(distance_squared);
+                            candidates.push(index);
+                        }
+                    }
+                }
+            }
+        }
+
+        candidates
+    }
+
+    // Every item whose cell overlaps the box reaching `radius` out from `position` on
+    // each side.
+    pub fn query_radius(&self, position: Vec2, radius: f32) -> Vec<usize> {
+        let (min_x, min_y) = Self::cell_coord(position - Vec2::splat(radius), self.cell_size);
+        let (max_x, max_y) = Self::cell_coord(position + Vec2::splat(radius), self.cell_size);
+
+        let mut results = Vec::new();
+
+        for cx in min_x..=max_x {
+            for cy in min_y..=max_y {
+                if let Some(indices) = self.cells.get(&(cx, cy)) {
+                    results.extend(indices);
+                }
+            }
+        }
+
+        results
+    }
+}
@@ -1,5 +1,16 @@
+use std::collections::VecDeque;
+
 use nannou::{geom::Rect, glam::{vec2, Vec2}, math::Vec2Angle};
 use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::brain::Brain;
+use crate::pheromone::PheromoneGrid;
+
+const RECENT_POSITION_CAPACITY: usize = 16;
+
+// Below this, a reading is decay residue rather than a real trail.
+const PHEROMONE_NEGLIGIBLE_INTENSITY: f32 = 0.01;
 
 fn map_range(value: f32, in_min: f32, in_max: f32, out_min: f32, out_max: f32) -> f32 {
     out_min + (value - in_min) * (out_max - out_min) / (in_max - in_min)
@@ -12,7 +23,7 @@ fn get_random_position(rect: Rect, rng: &mut impl Rng) -> Vec2 {
     )
 }
 
-#[derive(Default, Clone, Copy, Debug)]
+#[derive(Default, Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct Dna {
     pub hue: f32,
     pub max_velocity: f32,
@@ -21,11 +32,19 @@ pub struct Dna {
     pub food_detection_radius: f32,
     pub food_force_multiplier: f32,
     pub poison_detection_radius: f32,
-    pub poison_force_multiplier: f32
+    pub poison_force_multiplier: f32,
+
+    pub flock_detection_radius: f32,
+    pub separation_force_multiplier: f32,
+    pub alignment_force_multiplier: f32,
+    pub cohesion_force_multiplier: f32,
+
+    pub pheromone_follow_strength: f32,
+    pub pheromone_deposit_amount: f32,
 }
 
-impl Into<[f32; 8]> for Dna {
-    fn into(self) -> [f32; 8] {
+impl Into<[f32; 14]> for Dna {
+    fn into(self) -> [f32; 14] {
         [
             self.max_velocity,
             self.max_steer_force,
@@ -34,13 +53,19 @@ impl Into<[f32; 8]> for Dna {
             self.food_force_multiplier,
             self.poison_detection_radius,
             self.poison_force_multiplier,
-            self.hue
+            self.hue,
+            self.flock_detection_radius,
+            self.separation_force_multiplier,
+            self.alignment_force_multiplier,
+            self.cohesion_force_multiplier,
+            self.pheromone_follow_strength,
+            self.pheromone_deposit_amount,
         ]
     }
 }
 
-impl From<[f32; 8]> for Dna {
-    fn from(value: [f32; 8]) -> Self {
+impl From<[f32; 14]> for Dna {
+    fn from(value: [f32; 14]) -> Self {
         Dna {
             max_velocity: value[0],
             max_steer_force: value[1],
@@ -50,13 +75,54 @@ impl From<[f32; 8]> for Dna {
             poison_detection_radius: value[5],
             poison_force_multiplier: value[6],
             hue: value[7],
+            flock_detection_radius: value[8],
+            separation_force_multiplier: value[9],
+            alignment_force_multiplier: value[10],
+            cohesion_force_multiplier: value[11],
+            pheromone_follow_strength: value[12],
+            pheromone_deposit_amount: value[13],
         }
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CrossoverMethod {
+    /// Each gene independently comes from one parent or the other.
+    Uniform,
+    /// Genes before a random split point come from `self`, the rest from `other`.
+    SinglePoint,
+}
+
 impl Dna {
+    pub fn crossover(&self, other: &Dna, method: CrossoverMethod, rng: &mut impl Rng) -> Dna {
+        let genes_a: [f32; 14] = (*self).into();
+        let genes_b: [f32; 14] = (*other).into();
+
+        let child_genes = match method {
+            CrossoverMethod::Uniform => {
+                let mut child_genes = [0.0; 14];
+
+                for i in 0..child_genes.len() {
+                    child_genes[i] = if rng.gen_bool(0.5) { genes_a[i] } else { genes_b[i] };
+                }
+
+                child_genes
+            },
+            CrossoverMethod::SinglePoint => {
+                let split = rng.gen_range(0..genes_a.len());
+
+                let mut child_genes = genes_a;
+                child_genes[split..].copy_from_slice(&genes_b[split..]);
+
+                child_genes
+            },
+        };
+
+        Dna::from(child_genes)
+    }
+
     pub fn mutate(self, mutate_chance: f64, rng: &mut impl Rng) -> Dna {
-        let mut dna_array: [f32; 8] = self.into();
+        let mut dna_array: [f32; 14] = self.into();
 
         for i in 0..dna_array.len() {
             if rng.gen_bool(mutate_chance) {
@@ -79,10 +145,22 @@ impl Dna {
             food_force_multiplier: rng.gen_range(0.75..1.25),
             poison_detection_radius: rng.gen_range(64.0..192.0),
             poison_force_multiplier: rng.gen_range(0.75..1.25),
+            flock_detection_radius: rng.gen_range(32.0..128.0),
+            separation_force_multiplier: rng.gen_range(0.0..1.5),
+            alignment_force_multiplier: rng.gen_range(0.0..1.0),
+            cohesion_force_multiplier: rng.gen_range(0.0..1.0),
+            pheromone_follow_strength: rng.gen_range(0.0..1.0),
+            pheromone_deposit_amount: rng.gen_range(0.5..2.0),
         }
     }
 }
 
+#[derive(Clone, Copy)]
+pub struct Neighbor {
+    pub position: Vec2,
+    pub velocity: Vec2,
+}
+
 #[derive(Default)]
 pub struct SteeringAgent {
     pub position: Vec2,
@@ -95,7 +173,16 @@ pub struct SteeringAgent {
 
     pub wander_target: Option<Vec2>,
 
+    pub age: f32,
+
+    pub food_eaten: u32,
+    pub poison_touched: u32,
+
     pub dna: Dna,
+
+    pub brain: Option<Brain>,
+
+    pub recent_positions: VecDeque<Vec2>,
 }
 
 impl SteeringAgent {
@@ -107,6 +194,16 @@ impl SteeringAgent {
         }
     }
 
+    pub fn with_brain(mut self, brain: Brain) -> Self {
+        self.brain = Some(brain);
+        self
+    }
+
+    // Used by generational mode to rank agents for selection.
+    pub fn fitness(&self) -> f32 {
+        (self.food_eaten as f32 - self.poison_touched as f32) * self.age
+    }
+
     pub fn apply_force(&mut self, force: Vec2) {
         self.acceleration += force;
     }
@@ -136,9 +233,54 @@ impl SteeringAgent {
         self.hunger += 0.2;
     }
 
+    fn record_position(&mut self) {
+        self.recent_positions.push_front(self.position);
+        self.recent_positions.truncate(RECENT_POSITION_CAPACITY);
+    }
+
     pub fn update(&mut self) {
         self.update_position();
         self.update_hunger();
+        self.record_position();
+    }
+
+    // Lays down pheromone along the path the agent just took, called when it finds food.
+    pub fn deposit_pheromone(&self, grid: &mut PheromoneGrid, amount: f32) {
+        for &position in &self.recent_positions {
+            grid.deposit(position, amount);
+        }
+    }
+
+    // Samples pheromone intensity ahead of the agent's heading and steers up the
+    // gradient. Returns whether a trail was found to follow.
+    pub fn follow_pheromones(&mut self, grid: &PheromoneGrid, force_multiplier: f32) -> bool {
+        let sample_distance = grid.cell_size() * 2.;
+        let heading = if self.velocity.length() > 0. {
+            self.velocity.normalize()
+        } else {
+            vec2(1., 0.)
+        };
+
+        let mut best_position = None;
+        let mut best_intensity = PHEROMONE_NEGLIGIBLE_INTENSITY;
+
+        for angle_offset in [-0.5_f32, 0., 0.5] {
+            let angle = heading.angle() + angle_offset;
+            let sample_position = self.position + vec2(angle.cos(), angle.sin()) * sample_distance;
+            let intensity = grid.intensity_at(sample_position);
+
+            if intensity > best_intensity {
+                best_intensity = intensity;
+                best_position = Some(sample_position);
+            }
+        }
+
+        if let Some(position) = best_position {
+            self.seek(position, force_multiplier);
+            true
+        } else {
+            false
+        }
     }
 
     pub fn seek(&mut self, target: Vec2, force_multiplier: f32) {
@@ -169,6 +311,99 @@ impl SteeringAgent {
         self.apply_force(steering_force*-1. * force_multiplier);
     }
     
+    // Drives the agent with its `Brain` instead of the hand-written steering rules.
+    // Inputs are normalized so the same weights work regardless of evolved radii/speed.
+    pub fn think(&mut self, nearest_food: Option<Vec2>, nearest_poison: Option<Vec2>, max_hunger: f32) {
+        let Some(brain) = &self.brain else { return };
+
+        let food_vector = nearest_food
+            .map(|position| (position - self.position) / self.dna.food_detection_radius.max(1.0))
+            .unwrap_or(Vec2::ZERO);
+
+        let poison_vector = nearest_poison
+            .map(|position| (position - self.position) / self.dna.poison_detection_radius.max(1.0))
+            .unwrap_or(Vec2::ZERO);
+
+        let velocity = self.velocity / self.dna.max_velocity.max(0.001);
+        let hunger = (self.hunger / max_hunger).min(1.0);
+
+        let inputs = [
+            food_vector.x, food_vector.y,
+            poison_vector.x, poison_vector.y,
+            velocity.x, velocity.y,
+            hunger,
+        ];
+
+        let outputs = brain.feed_forward(&inputs);
+        let steering_force = vec2(outputs[0], outputs[1]).clamp_length_max(self.dna.max_steer_force);
+
+        self.apply_force(steering_force);
+    }
+
+    // Steers away from nearby neighbors, weighted inversely by distance.
+    pub fn separation(&mut self, neighbors: &[Neighbor], radius: f32, force_multiplier: f32) {
+        let mut steer = Vec2::ZERO;
+        let mut count = 0;
+
+        for neighbor in neighbors {
+            let difference = self.position - neighbor.position;
+            let distance = difference.length();
+
+            if distance > 0. && distance < radius {
+                steer += difference.normalize() / distance;
+                count += 1;
+            }
+        }
+
+        if count == 0 {
+            return;
+        }
+
+        let desired_velocity = (steer / count as f32).normalize_or_zero() * self.dna.max_velocity;
+        let steering_force = (desired_velocity - self.velocity).clamp_length_max(self.dna.max_steer_force);
+        self.apply_force(steering_force * force_multiplier);
+    }
+
+    // Steers toward the average heading of nearby neighbors.
+    pub fn alignment(&mut self, neighbors: &[Neighbor], radius: f32, force_multiplier: f32) {
+        let mut average_velocity = Vec2::ZERO;
+        let mut count = 0;
+
+        for neighbor in neighbors {
+            if self.position.distance_squared(neighbor.position) < radius * radius {
+                average_velocity += neighbor.velocity;
+                count += 1;
+            }
+        }
+
+        if count == 0 {
+            return;
+        }
+
+        let desired_velocity = (average_velocity / count as f32).normalize_or_zero() * self.dna.max_velocity;
+        let steering_force = (desired_velocity - self.velocity).clamp_length_max(self.dna.max_steer_force);
+        self.apply_force(steering_force * force_multiplier);
+    }
+
+    // Steers toward the centroid of nearby neighbors, via `arrive`.
+    pub fn cohesion(&mut self, neighbors: &[Neighbor], radius: f32, force_multiplier: f32) {
+        let mut centroid = Vec2::ZERO;
+        let mut count = 0;
+
+        for neighbor in neighbors {
+            if self.position.distance_squared(neighbor.position) < radius * radius {
+                centroid += neighbor.position;
+                count += 1;
+            }
+        }
+
+        if count == 0 {
+            return;
+        }
+
+        self.arrive(centroid / count as f32, force_multiplier);
+    }
+
     pub fn wander(&mut self, wander_rect: Rect, rng: &mut impl Rng) {
         let wander_target = if let Some(wander_target) = self.wander_target {
             wander_target